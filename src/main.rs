@@ -2,14 +2,18 @@ use std::any::Any;
 use std::cell::RefCell;
 use std::collections::hash_map::*;
 use std::rc::Rc;
+use std::sync::Arc;
 
 use polars::datatypes::DataType;
 use polars::export::rayon::iter::plumbing::Reducer;
+use polars::export::rayon::prelude::*;
 use polars::prelude::*;
 use polars::toggle_string_cache;
 use polars_plan::dsl::*;
 use rand::prelude::*;
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
+use rand::SeedableRng;
 
 #[derive(Clone, Debug)]
 struct TreeNode {
@@ -17,21 +21,66 @@ struct TreeNode {
     pub split_value: SplitValue,
     pub true_branch: i32,
     pub false_branch: i32,
+    /// `[n_c, n_pc, n_t, n_pt]` as returned by `UpliftTreeModel::summary` for
+    /// the rows that reached this node, kept around so `predict` can read a
+    /// leaf's treatment/control response rates without re-scanning the data.
+    pub summary: Vec<i32>,
 }
 
-impl TreeNode {
-    fn new() -> TreeNode {
-        TreeNode {
-            col_name: String::new(),
-            split_value: SplitValue::Numeric(0.),
-            true_branch: -1,
-            false_branch: -1,
-        }
+/// Clamp a probability away from the edges of `[0, 1]` so that divergence
+/// measures relying on `ln` or division never blow up on degenerate leaves.
+fn clamp_prob(p: f64) -> f64 {
+    p.max(1e-6).min(1. - 1e-6)
+}
+
+/// A pluggable uplift split criterion. `node_value` scores a single node
+/// from its treatment/control response rates, and `norm_entropy` supplies
+/// the leading entropy-like term used by `calc_norm` to penalize splits
+/// that skew the treatment/control ratio, so that the normalization stays
+/// consistent with whichever divergence drives the gain calculation.
+trait SplitCriterion: Send + Sync {
+    fn node_value(&self, p_treat: f64, p_ctrl: f64) -> f64;
+    fn norm_entropy(&self, p_treat: f64, p_ctrl: f64) -> f64;
+}
+
+struct Euclidean;
+
+impl SplitCriterion for Euclidean {
+    fn node_value(&self, p_treat: f64, p_ctrl: f64) -> f64 {
+        (p_treat - p_ctrl).powi(2)
+    }
+
+    fn norm_entropy(&self, p_treat: f64, p_ctrl: f64) -> f64 {
+        1. - p_treat.powi(2) - p_ctrl.powi(2)
+    }
+}
+
+struct KlDivergence;
+
+impl SplitCriterion for KlDivergence {
+    fn node_value(&self, p_treat: f64, p_ctrl: f64) -> f64 {
+        let p_t = clamp_prob(p_treat);
+        let p_c = clamp_prob(p_ctrl);
+        p_t * (p_t / p_c).ln() + (1. - p_t) * ((1. - p_t) / (1. - p_c)).ln()
+    }
+
+    fn norm_entropy(&self, p_treat: f64, p_ctrl: f64) -> f64 {
+        self.node_value(p_treat, p_ctrl)
     }
 }
 
-enum EvalFunc {
-    Euclidiean,
+struct ChiSquared;
+
+impl SplitCriterion for ChiSquared {
+    fn node_value(&self, p_treat: f64, p_ctrl: f64) -> f64 {
+        let p_t = clamp_prob(p_treat);
+        let p_c = clamp_prob(p_ctrl);
+        (p_t - p_c).powi(2) / p_c + ((1. - p_t) - (1. - p_c)).powi(2) / (1. - p_c)
+    }
+
+    fn norm_entropy(&self, p_treat: f64, p_ctrl: f64) -> f64 {
+        self.node_value(p_treat, p_ctrl)
+    }
 }
 
 #[derive(PartialEq, Clone, Debug)]
@@ -41,15 +90,27 @@ enum SplitValue {
 }
 
 struct UpliftTreeModel {
+    /// Arena of nodes produced by `build`. A node's `true_branch` /
+    /// `false_branch` are indices into this vec (or `-1` for a leaf), so the
+    /// tree grows with the number of nodes `build` actually creates instead
+    /// of `2^max_depth`.
     nodes: RefCell<Vec<TreeNode>>,
+    /// Index of the root node in `nodes`, set once `fit` has run; `-1` means
+    /// the model has not been fit yet. A root that did not split is a leaf
+    /// node stored at a valid index like any other.
+    root: i32,
     max_depth: i32,
     min_sample_leaf: i32,
     feature_sample_size: i32,
-    eval_func: EvalFunc,
+    eval_func: Arc<dyn SplitCriterion>,
     max_splits: i32,
     treatment_col: String,
     outcome_col: String,
     feature_cols: Vec<String>,
+    /// Seeded so that `feature_cols.choose_multiple` and the split-value
+    /// sampling in `calc_split_values` are reproducible across runs, and so
+    /// `UpliftForest` can hand each tree a distinct, explicit seed.
+    rng: RefCell<StdRng>,
 }
 
 impl UpliftTreeModel {
@@ -57,11 +118,13 @@ impl UpliftTreeModel {
         max_depth: i32,
         min_sample_leaf: i32,
         feature_sample_size: i32,
-        eval_func: EvalFunc,
+        eval_func: Arc<dyn SplitCriterion>,
         max_splits: i32,
+        seed: u64,
     ) -> UpliftTreeModel {
         UpliftTreeModel {
-            nodes: RefCell::new(vec![TreeNode::new(); 1 << max_depth - 1]),
+            nodes: RefCell::new(Vec::new()),
+            root: -1,
             max_depth,
             min_sample_leaf,
             feature_sample_size,
@@ -70,6 +133,7 @@ impl UpliftTreeModel {
             treatment_col: String::new(),
             outcome_col: String::new(),
             feature_cols: Vec::new(),
+            rng: RefCell::new(StdRng::seed_from_u64(seed)),
         }
     }
 
@@ -79,25 +143,45 @@ impl UpliftTreeModel {
         treatment_col: String,
         outcome_col: String,
     ) -> Result<(), PolarsError> {
-        self.treatment_col = treatment_col.clone();
-        self.outcome_col = outcome_col.clone();
-
         let data = LazyFrame::scan_parquet(data_file, Default::default())?.collect()?;
+        self.fit_frame(data, treatment_col, outcome_col)
+    }
+
+    /// Shared `fit` body taking an already-materialized frame. Casts feature
+    /// columns to the dtypes `build` expects, then fits via `fit_typed`;
+    /// `UpliftForest::fit` calls `fit_typed` directly instead, since its
+    /// bootstrap samples are drawn from a frame it has already cast once up
+    /// front.
+    fn fit_frame(
+        &mut self,
+        data: DataFrame,
+        treatment_col: String,
+        outcome_col: String,
+    ) -> Result<(), PolarsError> {
+        let data = UpliftTreeModel::cast_feature_cols(data, &treatment_col, &outcome_col)?;
+        self.fit_typed(data, treatment_col, outcome_col)
+    }
 
+    /// Cast string feature columns to `Categorical`, numeric feature columns
+    /// to `Float64`, and `treatment_col`/`outcome_col` to `Int32`, matching
+    /// the dtypes `build` expects of the training frame.
+    fn cast_feature_cols(
+        data: DataFrame,
+        treatment_col: &str,
+        outcome_col: &str,
+    ) -> Result<DataFrame, PolarsError> {
         let mut str_cols: Vec<String> = Vec::new();
         let mut numeric_cols: Vec<String> = Vec::new();
 
-        self.feature_cols = data
+        let feature_cols: Vec<String> = data
             .get_column_names_owned()
             .iter()
-            .filter(|&x| *x != treatment_col && *x != outcome_col)
+            .filter(|&x| x != treatment_col && x != outcome_col)
             .map(|x| x.to_owned())
             .collect();
 
-        assert!(self.feature_sample_size <= self.feature_cols.len() as i32);
-
         let schema = data.schema();
-        for f in &self.feature_cols {
+        for f in &feature_cols {
             let tp = schema.get(f).unwrap();
 
             if *tp == DataType::Utf8 {
@@ -116,17 +200,39 @@ impl UpliftTreeModel {
         for f in &numeric_cols {
             data = data.with_column(col(f).cast(DataType::Float64))
         }
-        data = data.with_column(col(&self.treatment_col).cast(DataType::Int32));
-        data = data.with_column(col(&self.outcome_col).cast(DataType::Int32));
+        data = data.with_column(col(treatment_col).cast(DataType::Int32));
+        data = data.with_column(col(outcome_col).cast(DataType::Int32));
+        data.collect()
+    }
+
+    /// Shared `fit` body for a frame whose feature columns are already cast
+    /// to the dtypes `build` expects (see `cast_feature_cols`).
+    fn fit_typed(
+        &mut self,
+        data: DataFrame,
+        treatment_col: String,
+        outcome_col: String,
+    ) -> Result<(), PolarsError> {
+        self.treatment_col = treatment_col.clone();
+        self.outcome_col = outcome_col.clone();
+        self.feature_cols = data
+            .get_column_names_owned()
+            .iter()
+            .filter(|&x| *x != treatment_col && *x != outcome_col)
+            .map(|x| x.to_owned())
+            .collect();
 
-        self.build(data.collect()?, 0, 0)?;
+        assert!(self.feature_sample_size <= self.feature_cols.len() as i32);
+
+        self.root = self.build(data, 0)?;
         Ok(())
     }
 
     fn calc_split_values(&self, col_values: &Series) -> Result<Vec<SplitValue>, PolarsError> {
         let unique_values = col_values.unique()?;
         let mut split_values: Vec<SplitValue> = Vec::new();
-        let rng = &mut rand::thread_rng();
+        let mut rng = self.rng.borrow_mut();
+        let rng = &mut *rng;
         if unique_values.len() <= self.max_splits as usize {
             if unique_values.dtype().is_numeric() {
                 unique_values
@@ -218,31 +324,43 @@ impl UpliftTreeModel {
 
     fn calc_score(&self, v: &Vec<i32>) -> f64 {
         assert!(v.len() == 4);
-        let p = v[1] as f64 / v[0] as f64;
-        let q = v[3] as f64 / v[2] as f64;
-        match self.eval_func {
-            EvalFunc::Euclidiean => (p - q).powi(2),
-        }
+        let p_c = v[1] as f64 / v[0] as f64;
+        let p_t = v[3] as f64 / v[2] as f64;
+        self.eval_func.node_value(p_t, p_c)
     }
 
-    fn calc_norm(n_c: i32, n_t: i32, n_c_left: i32, n_t_left: i32) -> f64 {
+    fn calc_norm(&self, n_c: i32, n_t: i32, n_c_left: i32, n_t_left: i32) -> f64 {
         let p_t = n_t as f64 / (n_t + n_c) as f64;
         let p_c = 1. - p_t;
         let p_c_left = n_c_left as f64 / (n_t_left + n_c_left) as f64;
         let p_t_left = 1. - p_c_left;
 
-        (1. - p_t.powi(2) - p_c.powi(2)) * (p_c_left - p_t_left).powi(2)
+        self.eval_func.norm_entropy(p_t, p_c) * (p_c_left - p_t_left).powi(2)
             + p_t * (1. - p_t_left.powi(2))
             + p_c * (1. - p_c_left.powi(2))
             + 0.5
     }
 
-    fn build(&self, data: DataFrame, cur_idx: usize, depth: i32) -> Result<i32, PolarsError> {
-        if depth >= self.max_depth {
-            return Ok(-1);
-        }
-        let rng = &mut rand::thread_rng();
+    /// Push a leaf node (no split, `true_branch`/`false_branch` both `-1`)
+    /// carrying `summary` so `predict` can read its uplift, and return its
+    /// arena index.
+    fn push_leaf(&self, summary: Vec<i32>) -> i32 {
+        let mut nodes = self.nodes.borrow_mut();
+        nodes.push(TreeNode {
+            col_name: String::new(),
+            split_value: SplitValue::Numeric(0.),
+            true_branch: -1,
+            false_branch: -1,
+            summary,
+        });
+        (nodes.len() - 1) as i32
+    }
+
+    fn build(&self, data: DataFrame, depth: i32) -> Result<i32, PolarsError> {
         let cur_summary = self.summary(&data)?;
+        if depth >= self.max_depth || cur_summary.len() != 4 {
+            return Ok(self.push_leaf(cur_summary));
+        }
         let cur_score = self.calc_score(&cur_summary);
         let n_c = cur_summary[0];
         let n_t = cur_summary[2];
@@ -252,10 +370,14 @@ impl UpliftTreeModel {
         let mut split_col = String::new();
         let mut split_value = SplitValue::Numeric(0.);
 
-        for f in self
-            .feature_cols
-            .choose_multiple(rng, self.feature_sample_size as usize)
-        {
+        let sampled_features: Vec<String> = {
+            let mut rng = self.rng.borrow_mut();
+            self.feature_cols
+                .choose_multiple(&mut *rng, self.feature_sample_size as usize)
+                .cloned()
+                .collect()
+        };
+        for f in &sampled_features {
             let split_values = self.calc_split_values(data.column(f)?)?;
             for v in split_values {
                 let (data_left, data_right) = self.split_set(v.clone(), f, data.clone())?;
@@ -274,7 +396,7 @@ impl UpliftTreeModel {
                 let n_c_left = left_summary[0];
                 let n_t_left = left_summary[2];
                 let gain = (left_score * p + right_score * (1. - p) - cur_score)
-                    / UpliftTreeModel::calc_norm(n_c, n_t, n_c_left, n_t_left);
+                    / self.calc_norm(n_c, n_t, n_c_left, n_t_left);
                 if gain > max_gain {
                     best_data_left = data_left;
                     best_data_right = data_right;
@@ -285,15 +407,191 @@ impl UpliftTreeModel {
             }
         }
         if max_gain > 0. && depth < self.max_depth {
-            let cur_node = &mut self.nodes.borrow_mut()[cur_idx];
-            cur_node.col_name = split_col;
-            cur_node.split_value = split_value;
-            cur_node.true_branch = self.build(best_data_left, 2 * cur_idx + 1, depth + 1)?;
-            cur_node.false_branch = self.build(best_data_right, 2 * cur_idx + 2, depth + 1)?;
-            Ok(cur_idx as i32)
+            let true_branch = self.build(best_data_left, depth + 1)?;
+            let false_branch = self.build(best_data_right, depth + 1)?;
+            let mut nodes = self.nodes.borrow_mut();
+            nodes.push(TreeNode {
+                col_name: split_col,
+                split_value,
+                true_branch,
+                false_branch,
+                summary: cur_summary,
+            });
+            Ok((nodes.len() - 1) as i32)
         } else {
-            Ok(-1)
+            Ok(self.push_leaf(cur_summary))
+        }
+    }
+
+    /// Estimated uplift `p_t - p_c` for a leaf's `summary`; `0.` for a leaf
+    /// that never saw both treatment and control rows.
+    fn leaf_uplift(summary: &[i32]) -> f64 {
+        if summary.len() != 4 {
+            return 0.;
+        }
+        let p_c = summary[1] as f64 / summary[0] as f64;
+        let p_t = summary[3] as f64 / summary[2] as f64;
+        p_t - p_c
+    }
+
+    /// Walk the fitted tree for a single row of `data`, following
+    /// `true_branch` when the row satisfies the node's split and
+    /// `false_branch` otherwise, and return the leaf's estimated uplift.
+    fn predict_row(&self, data: &DataFrame, row: usize) -> Result<f64, PolarsError> {
+        let nodes = self.nodes.borrow();
+        let mut cur = self.root;
+        loop {
+            if cur < 0 {
+                return Ok(0.);
+            }
+            let node = &nodes[cur as usize];
+            if node.true_branch < 0 && node.false_branch < 0 {
+                return Ok(UpliftTreeModel::leaf_uplift(&node.summary));
+            }
+            let col = data.column(&node.col_name)?;
+            let goes_true = match &node.split_value {
+                SplitValue::Numeric(v) => {
+                    let x = col.cast(&DataType::Float64)?.f64()?.get(row);
+                    x.map(|x| x <= *v).unwrap_or(false)
+                }
+                SplitValue::Str(v) => {
+                    let s = col.cast(&DataType::Utf8)?.utf8()?.get(row).map(|s| s.to_string());
+                    s.as_deref() == Some(v.as_str())
+                }
+            };
+            cur = if goes_true {
+                node.true_branch
+            } else {
+                node.false_branch
+            };
+        }
+    }
+
+    /// Score `data_file` with the fitted tree, returning each row's
+    /// estimated uplift `p_t - p_c` from the leaf it falls into.
+    pub fn predict(&self, data_file: String) -> Result<Series, PolarsError> {
+        let data = LazyFrame::scan_parquet(data_file, Default::default())?.collect()?;
+        let uplift: Vec<f64> = (0..data.height())
+            .map(|row| self.predict_row(&data, row))
+            .collect::<Result<Vec<f64>, PolarsError>>()?;
+        Ok(Series::new("uplift", uplift))
+    }
+}
+
+/// Bagged ensemble of `UpliftTreeModel`s. Each tree trains on an independent
+/// bootstrap sample of the training frame (with its own feature subset via
+/// `feature_sample_size`) and predictions are averaged across trees, which
+/// reduces the variance of the single-tree uplift estimate.
+struct UpliftForest {
+    trees: Vec<UpliftTreeModel>,
+    max_depth: i32,
+    min_sample_leaf: i32,
+    feature_sample_size: i32,
+    eval_func: Arc<dyn SplitCriterion>,
+    max_splits: i32,
+    seed: u64,
+}
+
+impl UpliftForest {
+    pub fn new(
+        max_depth: i32,
+        min_sample_leaf: i32,
+        feature_sample_size: i32,
+        eval_func: Arc<dyn SplitCriterion>,
+        max_splits: i32,
+        seed: u64,
+    ) -> UpliftForest {
+        UpliftForest {
+            trees: Vec::new(),
+            max_depth,
+            min_sample_leaf,
+            feature_sample_size,
+            eval_func,
+            max_splits,
+            seed,
+        }
+    }
+
+    /// Train `n_estimators` trees in parallel (via rayon) on bootstrap
+    /// samples of `subsample_frac` of the rows in `data_file`. The base
+    /// frame is scanned and its string columns resolved into the global
+    /// string cache once up front, since `toggle_string_cache` requires all
+    /// categorical casts drawn from the same cache to be comparable across
+    /// the bootstrap frames built for each tree.
+    pub fn fit(
+        &mut self,
+        data_file: String,
+        treatment_col: String,
+        outcome_col: String,
+        n_estimators: i32,
+        subsample_frac: f64,
+    ) -> Result<(), PolarsError> {
+        toggle_string_cache(true);
+        let base_data = LazyFrame::scan_parquet(data_file, Default::default())?.collect()?;
+        // Resolve categorical columns into the string cache once, on the base
+        // frame, before spawning the parallel bootstrap/build below — casting
+        // each worker's own sample independently would race distinct category
+        // codes against each other under the same global string cache.
+        let base_data =
+            UpliftTreeModel::cast_feature_cols(base_data, &treatment_col, &outcome_col)?;
+
+        let max_depth = self.max_depth;
+        let min_sample_leaf = self.min_sample_leaf;
+        let feature_sample_size = self.feature_sample_size;
+        let max_splits = self.max_splits;
+        let eval_func = &self.eval_func;
+        let seed = self.seed;
+
+        let trees: Result<Vec<UpliftTreeModel>, PolarsError> = (0..n_estimators)
+            .into_par_iter()
+            .map(|i| -> Result<UpliftTreeModel, PolarsError> {
+                let tree_seed = seed.wrapping_add(i as u64 + 1);
+                let sample = base_data.sample_frac(subsample_frac, true, true, Some(tree_seed))?;
+                let mut tree = UpliftTreeModel::new(
+                    max_depth,
+                    min_sample_leaf,
+                    feature_sample_size,
+                    eval_func.clone(),
+                    max_splits,
+                    tree_seed,
+                );
+                tree.fit_typed(sample, treatment_col.clone(), outcome_col.clone())?;
+                Ok(tree)
+            })
+            .collect();
+        self.trees = trees?;
+        Ok(())
+    }
+
+    /// Score `data_file` with every tree in the forest and return the mean
+    /// leaf uplift across trees for each row. Trees are walked sequentially
+    /// here (unlike `fit`'s rayon fan-out): each `UpliftTreeModel` keeps its
+    /// build-time scratch state (`nodes`, `rng`) behind `RefCell`, so it is
+    /// `!Sync` and can't be shared across threads via `par_iter`.
+    pub fn predict(&self, data_file: String) -> Result<Series, PolarsError> {
+        let data = LazyFrame::scan_parquet(data_file, Default::default())?.collect()?;
+        let per_tree: Result<Vec<Vec<f64>>, PolarsError> = self
+            .trees
+            .iter()
+            .map(|tree| -> Result<Vec<f64>, PolarsError> {
+                (0..data.height())
+                    .map(|row| tree.predict_row(&data, row))
+                    .collect()
+            })
+            .collect();
+        let per_tree = per_tree?;
+
+        let n_trees = (per_tree.len().max(1)) as f64;
+        let mut uplift = vec![0.0f64; data.height()];
+        for tree_preds in &per_tree {
+            for (u, p) in uplift.iter_mut().zip(tree_preds) {
+                *u += p;
+            }
+        }
+        for u in uplift.iter_mut() {
+            *u /= n_trees;
         }
+        Ok(Series::new("uplift", uplift))
     }
 }
 